@@ -1,3 +1,4 @@
+use std::collections::BTreeSet;
 use std::fs;
 use std::io;
 use std::io::Write; // For buf in logger.
@@ -5,11 +6,12 @@ use std::path::{Path, PathBuf};
 
 use anyhow::{bail, Context}; // Have to import Context trait for with_context.
 use atty;
+use clap::Parser;
 use env_logger;
-use git2::{DiffFormat, DiffOptions, Repository};
+use git2::{Commit, DiffFormat, DiffOptions, Repository, Sort, Time};
 use log::{debug, info, LevelFilter};
 
-const DEFAULT_REMOTE_HEAD: &str = "refs/remotes/origin/HEAD";
+const DEFAULT_REMOTE: &str = "origin";
 // When printing a diff, we need to prefix certain lines with an extra
 // character, if that line indicates it has a certain type of "origin"
 // (see DiffLine in git2). These origins are exactly what diff_print_to_buf
@@ -17,6 +19,18 @@ const DEFAULT_REMOTE_HEAD: &str = "refs/remotes/origin/HEAD";
 // to `git diff`.
 const GIT_DIFF_ORIGINS_TO_PRINT: [char; 3] = ['+', '-', ' '];
 
+// This is the fixed, nonsensical date used on the "From <oid> ..." separator
+// line of an mbox file. `git format-patch` always emits this exact date on
+// that line; it's not meant to convey any real time, just to give `git am`
+// and other mbox parsers something that looks like the output of `From_`
+// quoting. The real commit date goes on the `Date:` header below it.
+const FORMAT_PATCH_MAGIC_DATE: &str = "Mon Sep 17 00:00:00 2001";
+
+const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
 /*
 Define a set of characters we consider unsafe in filenames.
 On Windows, for instance, these characters are not allowed in filenames:
@@ -26,6 +40,49 @@ plus we replace . because we are adding our own extension.
 */
 const FILENAME_FORBIDDEN_CHARS: [char; 10] = ['/', '<', '>', ':', '"', '\\', '|', '?', '*', '.'];
 
+/// Split a combined diff (or a local git repo's unmerged commits) into one
+/// file per change, so a stacked PR can be reviewed and landed piece by
+/// piece.
+#[derive(Parser, Debug)]
+#[command(author, version, about)]
+struct Cli {
+    /// Name of the git remote to diff against.
+    #[arg(long, default_value = DEFAULT_REMOTE)]
+    remote: String,
+
+    /// Ref to compute the merge base against, instead of <remote>/HEAD.
+    #[arg(long)]
+    base: Option<String>,
+
+    /// Diff the index (staged changes) against the merge base, instead of
+    /// committed HEAD.
+    #[arg(long, conflicts_with = "worktree")]
+    staged: bool,
+
+    /// Diff the working directory (uncommitted edits) instead of committed
+    /// HEAD.
+    #[arg(long, conflicts_with = "staged")]
+    worktree: bool,
+
+    /// Directory to write the split .diff/.patch files into.
+    #[arg(long, default_value = ".")]
+    output_dir: PathBuf,
+
+    /// Emit one mbox-style patch per commit (`git format-patch` style)
+    /// instead of one .diff file per changed file.
+    #[arg(long)]
+    format_patch: bool,
+
+    /// Check that each split patch applies cleanly before writing it out.
+    #[arg(long)]
+    verify: bool,
+
+    /// Diff two filesystem paths directly (files or directories), without
+    /// needing a git repository. Mirrors `git diff --no-index`.
+    #[arg(long, num_args = 2, value_names = ["OLD", "NEW"])]
+    no_index: Option<Vec<PathBuf>>,
+}
+
 // TODO: Use miette to colorize error output?
 fn main() -> anyhow::Result<()> {
     env_logger::Builder::new()
@@ -34,7 +91,35 @@ fn main() -> anyhow::Result<()> {
         .parse_default_env()
         .init();
 
+    let cli = Cli::parse();
+    fs::create_dir_all(&cli.output_dir)
+        .with_context(|| format!("failed to create output directory {:#?}", cli.output_dir))?;
+
+    if let Some(paths) = &cli.no_index {
+        let old_path = &paths[0];
+        let new_path = &paths[1];
+        info!(
+            "Diffing {:#?} against {:#?} without a git repository.",
+            old_path, new_path
+        );
+        let diff_text = diff_paths_no_index(old_path, new_path)?;
+        let patch_files = split_diff(diff_text)?;
+        write_out_new_diffs(patch_files, &cli.output_dir)?;
+        return Ok(());
+    }
+
+    if cli.format_patch {
+        let repo = Repository::discover(Path::new("."))
+            .context("--format-patch requires running inside a git repository")?;
+        info!("Generating one format-patch file per commit against remote head.");
+        let patches = get_format_patches_from_repo(&repo, &cli)
+            .with_context(|| format!("failed to build format-patches in {:#?}", repo.path()))?;
+        write_format_patches(patches, &cli.output_dir)?;
+        return Ok(());
+    }
+
     let mut input = String::new();
+    let mut repo_for_verify: Option<Repository> = None;
     if atty::isnt(atty::Stream::Stdin) {
         info!("Detected input on stdin, reading a diff from stdin.");
         input = io::read_to_string(io::stdin())?;
@@ -42,8 +127,9 @@ fn main() -> anyhow::Result<()> {
         match Repository::discover(Path::new(".")) {
             Ok(repo) => {
                 info!("Diffing the local git repository against remote head.");
-                input = get_diff_from_repo(&repo)
+                input = get_diff_from_repo(&repo, &cli)
                     .with_context(|| format!("failed to do a git diff in {:#?}", repo.path()))?;
+                repo_for_verify = Some(repo);
             }
             Err(e) => {
                 debug!("No git repo found: {}", e)
@@ -54,11 +140,76 @@ fn main() -> anyhow::Result<()> {
         bail!("No input found on stdin, and local directory is not a git repo where the commits differ from remote head.");
     }
     let patch_files = split_diff(input)?;
-    write_out_new_diffs(patch_files)?;
+
+    if cli.verify {
+        let repo = repo_for_verify
+            .as_ref()
+            .context("--verify requires running against a git repository, not stdin input")?;
+        let (_, merge_base_commit) =
+            find_merge_base_commits(repo, &cli.remote, cli.base.as_deref())?;
+        verify_patches_apply(repo, &merge_base_commit.tree()?, &patch_files)?;
+    }
+
+    write_out_new_diffs(patch_files, &cli.output_dir)?;
+    Ok(())
+}
+
+/*
+Re-parses each already-split patch and checks (without writing anything)
+that it would apply cleanly on top of the merge-base tree, the same base
+`split_diff`'s input was diffed against. This catches the case where
+splitting a combined diff into per-file patches produced something that no
+longer applies on its own -- e.g. because hunk context leaked across a file
+boundary -- so we can fail loudly with the offending file's name instead of
+shipping a patch that breaks later. Only the merge-base tree is checked; the
+current worktree and index are not consulted.
+*/
+fn verify_patches_apply(
+    repo: &Repository,
+    merge_base_tree: &git2::Tree,
+    patch_files: &[PatchFile],
+) -> anyhow::Result<()> {
+    let mut failures = Vec::new();
+    for pf in patch_files {
+        let diff = git2::Diff::from_buffer(pf.contents.as_bytes())
+            .with_context(|| format!("failed to re-parse generated patch for {}", pf.new))?;
+
+        let mut scratch_index = git2::Index::new()?;
+        scratch_index.read_tree(merge_base_tree)?;
+
+        let mut apply_opts = git2::ApplyOptions::new();
+        apply_opts.check(true);
+
+        let mut original_index = repo.index()?;
+        repo.set_index(&mut scratch_index)?;
+        let result = repo.apply(&diff, git2::ApplyLocation::Index, Some(&mut apply_opts));
+        repo.set_index(&mut original_index)?;
+
+        if let Err(e) = result {
+            failures.push(format!("{} (from {}): {}", pf.new, pf.old, e));
+        }
+    }
+
+    if !failures.is_empty() {
+        bail!(
+            "{} of {} split patch(es) would not apply cleanly:\n{}",
+            failures.len(),
+            patch_files.len(),
+            failures.join("\n")
+        );
+    }
     Ok(())
 }
 
-fn get_diff_from_repo(repo: &Repository) -> anyhow::Result<String> {
+// Finds the merge base between local head and the remote head, and returns
+// (local head commit, merge base commit). Shared by the single-diff path
+// and the per-commit format-patch path, since both need to know where the
+// range of "commits the user hasn't merged yet" begins.
+fn find_merge_base_commits<'repo>(
+    repo: &'repo Repository,
+    remote: &str,
+    base: Option<&str>,
+) -> anyhow::Result<(Commit<'repo>, Commit<'repo>)> {
     /*
     We want to find the "merge base commit." Basically, we want to know
     the differences between our repo and what origin would have looked
@@ -70,14 +221,33 @@ fn get_diff_from_repo(repo: &Repository) -> anyhow::Result<String> {
     */
 
     let local_head = repo.head()?.peel_to_commit()?;
-    // TODO: Allow user to specify a different remote.
-    let remote_head = repo
-        .find_reference(&DEFAULT_REMOTE_HEAD)
-        .context("could not find remote origin for the repo")?
-        .peel_to_commit()?;
-    let merge_base_oid = repo.merge_base(local_head.id(), remote_head.id())?;
+    let base_commit = match base {
+        // A user-specified base can be any revision git understands (a
+        // branch, tag, or bare SHA), not just a ref path.
+        Some(base_ref) => repo
+            .revparse_single(base_ref)
+            .with_context(|| format!("could not resolve base ref {:#?}", base_ref))?
+            .peel_to_commit()?,
+        None => {
+            let remote_head_ref = format!("refs/remotes/{}/HEAD", remote);
+            repo.find_reference(&remote_head_ref)
+                .with_context(|| {
+                    format!(
+                        "could not find {:#?} (is {:#?} a configured remote?)",
+                        remote_head_ref, remote
+                    )
+                })?
+                .peel_to_commit()?
+        }
+    };
+    let merge_base_oid = repo.merge_base(local_head.id(), base_commit.id())?;
     let merge_base_commit = repo.find_commit(merge_base_oid)?;
-    let local_head_tree = local_head.tree()?;
+    Ok((local_head, merge_base_commit))
+}
+
+fn get_diff_from_repo(repo: &Repository, cli: &Cli) -> anyhow::Result<String> {
+    let (local_head, merge_base_commit) =
+        find_merge_base_commits(repo, &cli.remote, cli.base.as_deref())?;
     let merge_base_tree = merge_base_commit.tree()?;
 
     // This is one difference from the normal behavior of git diff: we need to show
@@ -85,21 +255,187 @@ fn get_diff_from_repo(repo: &Repository) -> anyhow::Result<String> {
     // that `git diff --binary` and this code produce different text output, but they
     // create identical results when applied. There's a difference in encoding and/or
     // compression between git and libgit2.
+    // We leave libgit2's default text/eol handling in place (no
+    // `force_text`/`ignore_whitespace_eol` overrides), and `split_diff` no
+    // longer normalizes line endings, so the line bytes `diff.print` hands
+    // us reach the output unchanged -- CRLF content stays CRLF. Those bytes
+    // are the blob contents (for `--staged`/`--worktree`, as git's clean
+    // filters leave them); no checkout-side eol conversion is applied, so a
+    // file that gets CRLF only from `core.autocrlf` or an `eol` attribute
+    // comes out with the line endings stored in the repository.
     let mut diff_opts = DiffOptions::new();
     diff_opts.show_binary(true);
 
-    // TODO: Provide an option to choose between diffing against the workdir and
-    // diffing against committed head.
-    //
-    // We default to using the committed head because we assume that the user's
-    // intent is to create diffs against what would be pushed as a PR if they
-    // pushed right now.
-    let diff = repo.diff_tree_to_tree(
-        Some(&merge_base_tree),
-        Some(&local_head_tree),
+    // We default to using the committed head because we assume that the
+    // user's intent is to create diffs against what would be pushed as a PR
+    // if they pushed right now. `--staged`/`--worktree` let them instead
+    // split uncommitted changes before they've even made a commit.
+    let mut diff = if cli.staged {
+        let index = repo.index()?;
+        repo.diff_tree_to_index(Some(&merge_base_tree), Some(&index), Some(&mut diff_opts))?
+    } else if cli.worktree {
+        repo.diff_tree_to_workdir_with_index(Some(&merge_base_tree), Some(&mut diff_opts))?
+    } else {
+        let local_head_tree = local_head.tree()?;
+        repo.diff_tree_to_tree(
+            Some(&merge_base_tree),
+            Some(&local_head_tree),
+            Some(&mut diff_opts),
+        )?
+    };
+    detect_renames(&mut diff)?;
+
+    Ok(print_diff_to_patch_text(&diff)?)
+}
+
+// Enables rename and copy detection on an already-computed diff. Without
+// this, a file that was moved (or moved and edited) shows up as a plain
+// delete + add, which loses the "this is the same file" information. With
+// it, a pure rename prints only `rename from`/`rename to` headers and no
+// `--- `/`+++ ` pair; `split_diff` takes the names from those headers.
+fn detect_renames(diff: &mut git2::Diff) -> anyhow::Result<()> {
+    let mut find_opts = git2::DiffFindOptions::new();
+    find_opts.renames(true);
+    find_opts.copies(true);
+    diff.find_similar(Some(&mut find_opts))?;
+    Ok(())
+}
+
+/*
+Mirrors `git diff --no-index`: diffs two filesystem paths that don't need
+to live in a git repository at all, e.g. two release tarballs or two
+vendored dependency snapshots the user has unpacked side by side. Returns
+the same kind of multi-file diff text `get_diff_from_repo` does, so it can
+be fed straight into the existing `split_diff`/`write_out_new_diffs`
+pipeline.
+*/
+fn diff_paths_no_index(old_path: &Path, new_path: &Path) -> anyhow::Result<String> {
+    if old_path.is_dir() || new_path.is_dir() {
+        return diff_dirs_no_index(old_path, new_path);
+    }
+    let old_bytes =
+        fs::read(old_path).with_context(|| format!("failed to read {:#?}", old_path))?;
+    let new_bytes =
+        fs::read(new_path).with_context(|| format!("failed to read {:#?}", new_path))?;
+    diff_blobs_no_index(
+        Some(&old_bytes),
+        Some(&new_bytes),
+        &old_path.to_string_lossy(),
+        &new_path.to_string_lossy(),
+    )
+}
+
+// Walks both directory trees, pairs files up by the path relative to each
+// root, and diffs each pair (treating a file missing on one side as empty,
+// same as git does for an add or a delete).
+fn diff_dirs_no_index(old_root: &Path, new_root: &Path) -> anyhow::Result<String> {
+    let mut relative_paths = BTreeSet::new();
+    collect_relative_files(old_root, old_root, &mut relative_paths)?;
+    collect_relative_files(new_root, new_root, &mut relative_paths)?;
+
+    let mut diff_text = String::new();
+    for relative_path in relative_paths {
+        let old_file = old_root.join(&relative_path);
+        let new_file = new_root.join(&relative_path);
+        let old_bytes = read_if_file(&old_file)?;
+        let new_bytes = read_if_file(&new_file)?;
+        if old_bytes.is_none() && new_bytes.is_none() {
+            continue;
+        }
+        // Skip byte-identical files ourselves rather than relying on
+        // `Patch::from_buffers` emitting nothing for a zero-hunk delta --
+        // a zero-hunk delta can still print a `diff --git`/`index` header
+        // line, which `split_diff` would then pick up as a spurious file.
+        if old_bytes == new_bytes {
+            continue;
+        }
+        let relative_path_str = relative_path.to_string_lossy();
+        diff_text.push_str(&diff_blobs_no_index(
+            old_bytes.as_deref(),
+            new_bytes.as_deref(),
+            &relative_path_str,
+            &relative_path_str,
+        )?);
+    }
+    Ok(diff_text)
+}
+
+fn read_if_file(path: &Path) -> anyhow::Result<Option<Vec<u8>>> {
+    if !path.is_file() {
+        return Ok(None);
+    }
+    Ok(Some(
+        fs::read(path).with_context(|| format!("failed to read {:#?}", path))?,
+    ))
+}
+
+fn collect_relative_files(
+    dir: &Path,
+    root: &Path,
+    out: &mut BTreeSet<PathBuf>,
+) -> anyhow::Result<()> {
+    if !dir.is_dir() {
+        return Ok(());
+    }
+    for entry in
+        fs::read_dir(dir).with_context(|| format!("failed to read directory {:#?}", dir))?
+    {
+        let entry = entry?;
+        let path = entry.path();
+        // Use the entry's own (non-following) file type rather than
+        // `path.is_dir()`/`path.is_file()`, which follow symlinks: a
+        // symlinked directory -- including a self-referential one, as can
+        // show up in a vendored `node_modules` -- would otherwise send us
+        // into infinite recursion instead of an error.
+        let file_type = entry
+            .file_type()
+            .with_context(|| format!("failed to stat {:#?}", path))?;
+        if file_type.is_symlink() {
+            bail!("{:#?} is a symlink, which --no-index does not follow", path);
+        } else if file_type.is_dir() {
+            collect_relative_files(&path, root, out)?;
+        } else {
+            out.insert(
+                path.strip_prefix(root)
+                    .expect("walked path must be under its own root")
+                    .to_path_buf(),
+            );
+        }
+    }
+    Ok(())
+}
+
+// Builds a blob-vs-blob patch (one `diff --git` file entry, with its
+// `--- `/`+++ ` headers and hunks) from two in-memory byte buffers, with no
+// git repository or object database involved.
+fn diff_blobs_no_index(
+    old_bytes: Option<&[u8]>,
+    new_bytes: Option<&[u8]>,
+    old_label: &str,
+    new_label: &str,
+) -> anyhow::Result<String> {
+    let mut diff_opts = DiffOptions::new();
+    diff_opts.show_binary(true);
+    let mut patch = git2::Patch::from_buffers(
+        old_bytes,
+        Some(old_label),
+        new_bytes,
+        Some(new_label),
         Some(&mut diff_opts),
     )?;
 
+    let mut diff_text = String::new();
+    patch.print(&mut |_delta, _hunk, line: git2::DiffLine| {
+        if GIT_DIFF_ORIGINS_TO_PRINT.contains(&line.origin()) {
+            diff_text.push(line.origin());
+        }
+        diff_text.push_str(&String::from_utf8_lossy(line.content()));
+        true
+    })?;
+    Ok(diff_text)
+}
+
+fn print_diff_to_patch_text(diff: &git2::Diff) -> anyhow::Result<String> {
     let mut diff_text = String::new();
     diff.print(DiffFormat::Patch, |_delta, _hunk, line| {
         // This algorithm is similar to the one inside libgit2 for printing
@@ -114,6 +450,231 @@ fn get_diff_from_repo(repo: &Repository) -> anyhow::Result<String> {
     Ok(diff_text)
 }
 
+#[derive(Debug)]
+struct FormatPatch {
+    filename: String,
+    contents: String,
+}
+
+/*
+Walks merge_base..local_head (oldest commit first, matching `git
+format-patch`'s numbering) and builds one mbox-style patch per commit, so
+that a stacked branch can be handed to reviewers or `git am` one commit at
+a time instead of as a single combined diff that throws away authorship
+and the commit message.
+*/
+fn get_format_patches_from_repo(repo: &Repository, cli: &Cli) -> anyhow::Result<Vec<FormatPatch>> {
+    let (local_head, merge_base_commit) =
+        find_merge_base_commits(repo, &cli.remote, cli.base.as_deref())?;
+
+    let mut revwalk = repo.revwalk()?;
+    revwalk.push(local_head.id())?;
+    revwalk.hide(merge_base_commit.id())?;
+    revwalk.set_sorting(Sort::TOPOLOGICAL | Sort::REVERSE)?;
+    let oids = revwalk.collect::<Result<Vec<_>, _>>()?;
+
+    let mut diff_opts = DiffOptions::new();
+    diff_opts.show_binary(true);
+
+    let total = oids.len();
+    let mut patches = Vec::with_capacity(total);
+    for (i, oid) in oids.into_iter().enumerate() {
+        let commit = repo.find_commit(oid)?;
+        let parent_tree = match commit.parent_count() {
+            0 => None,
+            _ => Some(commit.parent(0)?.tree()?),
+        };
+        let commit_tree = commit.tree()?;
+        let mut diff = repo.diff_tree_to_tree(
+            parent_tree.as_ref(),
+            Some(&commit_tree),
+            Some(&mut diff_opts),
+        )?;
+        detect_renames(&mut diff)?;
+        let diff_text = print_diff_to_patch_text(&diff)?;
+        patches.push(format_patch_for_commit(&commit, i + 1, total, &diff_text)?);
+    }
+    Ok(patches)
+}
+
+fn format_patch_for_commit(
+    commit: &Commit,
+    index: usize,
+    total: usize,
+    diff_text: &str,
+) -> anyhow::Result<FormatPatch> {
+    let author = commit.author();
+    let author_name = author.name().unwrap_or("Unknown").to_string();
+    let author_email = author.email().unwrap_or("unknown@example.com").to_string();
+    let message = commit.message().unwrap_or("").to_string();
+    let mut message_lines = message.splitn(2, '\n');
+    let subject = message_lines.next().unwrap_or("").trim();
+    let body = message_lines.next().unwrap_or("").trim_start_matches('\n');
+
+    let mut contents = String::new();
+    contents.push_str(&format!(
+        "From {} {}\n",
+        commit.id(),
+        FORMAT_PATCH_MAGIC_DATE
+    ));
+    contents.push_str(&format!(
+        "From: {} <{}>\n",
+        rfc2047_encode(&author_name),
+        author_email
+    ));
+    contents.push_str(&format!("Date: {}\n", format_rfc2822(&author.when())));
+    contents.push_str(&format!(
+        "Subject: [PATCH {}/{}] {}\n",
+        index,
+        total,
+        rfc2047_encode(subject)
+    ));
+    contents.push('\n');
+    let body = body.trim_end_matches('\n');
+    if !body.is_empty() {
+        contents.push_str(&quote_mbox_from_lines(body));
+        contents.push('\n');
+        contents.push('\n');
+    }
+    contents.push_str(diff_text);
+    contents.push_str("-- \n");
+
+    Ok(FormatPatch {
+        filename: format!("{:04}-{}.patch", index, slugify_subject(subject)),
+        contents,
+    })
+}
+
+// Every patch file we emit starts with a magic "From <oid> ..." line, which
+// makes the whole file look like an mbox to `git am`/`git mailsplit`. If the
+// commit body itself contains a blank line followed by a line starting with
+// "From ", that line would be mistaken for the start of the *next* message,
+// truncating the patch before the diff. `git format-patch` guards against
+// this by prepending a `>` to any such line (and to any line that's already
+// `>`-quoted, so the quoting round-trips); we do the same here.
+fn quote_mbox_from_lines(body: &str) -> String {
+    let mut out = String::with_capacity(body.len());
+    for line in body.split_inclusive('\n') {
+        if is_mbox_from_line(line) {
+            out.push('>');
+        }
+        out.push_str(line);
+    }
+    out
+}
+
+fn is_mbox_from_line(line: &str) -> bool {
+    line.trim_start_matches('>').starts_with("From ")
+}
+
+// RFC 2047-encodes a header value if it contains non-ASCII characters, since
+// mbox/RFC 2822 headers are otherwise limited to US-ASCII. Commit subjects
+// and author names are free-form UTF-8, so this can actually come up.
+fn rfc2047_encode(value: &str) -> String {
+    if value.is_ascii() {
+        return value.to_string();
+    }
+    format!("=?UTF-8?B?{}?=", base64_encode(value.as_bytes()))
+}
+
+fn base64_encode(data: &[u8]) -> String {
+    const TABLE: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let b0 = chunk[0] as u32;
+        let b1 = *chunk.get(1).unwrap_or(&0) as u32;
+        let b2 = *chunk.get(2).unwrap_or(&0) as u32;
+        let n = (b0 << 16) | (b1 << 8) | b2;
+        out.push(TABLE[((n >> 18) & 0x3f) as usize] as char);
+        out.push(TABLE[((n >> 12) & 0x3f) as usize] as char);
+        out.push(if chunk.len() > 1 {
+            TABLE[((n >> 6) & 0x3f) as usize] as char
+        } else {
+            '='
+        });
+        out.push(if chunk.len() > 2 {
+            TABLE[(n & 0x3f) as usize] as char
+        } else {
+            '='
+        });
+    }
+    out
+}
+
+// Turns a commit subject into the kebab-case slug `git format-patch` uses
+// for its output filenames, e.g. "Fix the foo bug!" -> "Fix-the-foo-bug".
+fn slugify_subject(subject: &str) -> String {
+    let mut slug = String::new();
+    let mut last_was_dash = false;
+    for c in subject.chars() {
+        if c.is_ascii_alphanumeric() || c == '_' {
+            slug.push(c);
+            last_was_dash = false;
+        } else if !last_was_dash {
+            slug.push('-');
+            last_was_dash = true;
+        }
+    }
+    slug.trim_matches('-').to_string()
+}
+
+// Formats a git2::Time as the RFC 2822 date `git format-patch` puts in its
+// `Date:` header. We hand-roll this instead of pulling in a date/time crate,
+// since a commit timestamp plus a fixed UTC offset is all we need.
+fn format_rfc2822(time: &Time) -> String {
+    let offset_minutes = time.offset_minutes();
+    let local_seconds = time.seconds() + i64::from(offset_minutes) * 60;
+    let days = local_seconds.div_euclid(86400);
+    let seconds_of_day = local_seconds.rem_euclid(86400);
+    let (year, month, day) = civil_from_days(days);
+    let hour = seconds_of_day / 3600;
+    let minute = (seconds_of_day % 3600) / 60;
+    let second = seconds_of_day % 60;
+    // 1970-01-01 (day 0) was a Thursday.
+    let weekday = WEEKDAYS[days.rem_euclid(7) as usize];
+    let month_name = MONTHS[(month - 1) as usize];
+    let sign = if offset_minutes < 0 { '-' } else { '+' };
+    let abs_offset = offset_minutes.unsigned_abs();
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} {}{:02}{:02}",
+        weekday,
+        day,
+        month_name,
+        year,
+        hour,
+        minute,
+        second,
+        sign,
+        abs_offset / 60,
+        abs_offset % 60
+    )
+}
+
+// Howard Hinnant's `civil_from_days`: converts a day count since the Unix
+// epoch into a (year, month, day) in the proleptic Gregorian calendar.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = if z >= 0 { z } else { z - 146096 } / 146097;
+    let doe = (z - era * 146097) as u64;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let y = yoe as i64 + era * 400;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = if month <= 2 { y + 1 } else { y };
+    (year, month, day)
+}
+
+fn write_format_patches(patches: Vec<FormatPatch>, output_dir: &Path) -> Result<(), io::Error> {
+    for patch in patches {
+        let path = output_dir.join(&patch.filename);
+        info!("Writing: {}", path.to_string_lossy());
+        fs::write(path, patch.contents)?;
+    }
+    Ok(())
+}
+
 #[derive(Debug)]
 struct PatchFile {
     old: String,
@@ -132,14 +693,23 @@ need to re-use the string again.
 */
 fn split_diff(diff: String) -> anyhow::Result<Vec<PatchFile>> {
     let mut patch_files = Vec::new();
-    let mut current_file_lines = Vec::new();
+    let mut current_file_contents = String::new();
     let mut old_file_name = String::new();
     let mut new_file_name = String::new();
+    // Tracks how much to trust the current old/new names, so a later,
+    // lower-priority header can't clobber a name we're more sure of. Order,
+    // highest priority first: explicit rename/copy lines, then `--- `/`+++ `,
+    // then the `diff --git a/<old> b/<new>` header (our fallback for pure
+    // renames, which have no `--- `/`+++ ` pair at all).
+    let mut old_priority = -1i8;
+    let mut new_priority = -1i8;
 
-    // TODO: We will need to deal with outputting CRLF correctly, in the future.
-    // Although to be fair, I'm not sure that actually matters for most patch
-    // tools. Can use diff.split_inclusive('\n').
-    for line in diff.lines() {
+    // We iterate with split_inclusive instead of lines() so every line keeps
+    // its own terminator (or lack of one, for a final line with none). That
+    // lets us rebuild each file's contents by plain concatenation below,
+    // rather than re-joining with a blanket "\n" that would normalize CRLF
+    // input to LF and silently invent a newline on the last line.
+    for line in diff.split_inclusive('\n') {
         /*
         In many patch formats, such as git, this is the indicator that
         we are starting a new file.
@@ -153,18 +723,51 @@ fn split_diff(diff: String) -> anyhow::Result<Vec<PatchFile>> {
             patch_files.push(PatchFile {
                 old: old_file_name.clone(),
                 new: new_file_name.clone(),
-                contents: current_file_lines.join("\n"),
+                contents: std::mem::take(&mut current_file_contents),
             });
-            current_file_lines.clear();
             old_file_name.clear();
             new_file_name.clear();
-        } else if line.starts_with("--- ") {
-            old_file_name = fix_filename_in_diff(line[4..].to_owned());
-        } else if line.starts_with("+++ ") {
-            new_file_name = fix_filename_in_diff(line[4..].to_owned());
+            old_priority = -1;
+            new_priority = -1;
         }
 
-        current_file_lines.push(line);
+        // Header lines carry file names, not diff content, so we match on
+        // them with any line terminator stripped off.
+        let header_line = line.trim_end_matches(['\r', '\n']);
+        if header_line.starts_with("diff --git ") {
+            // Lowest priority: a fallback in case this is a pure rename or
+            // copy with no `--- `/`+++ ` pair to key off of.
+            if let Some((old, new)) = parse_diff_git_header(header_line) {
+                set_if_higher_priority(&mut old_file_name, &mut old_priority, old, 0);
+                set_if_higher_priority(&mut new_file_name, &mut new_priority, new, 0);
+            }
+        } else if let Some(old) = header_line.strip_prefix("--- ") {
+            set_if_higher_priority(
+                &mut old_file_name,
+                &mut old_priority,
+                fix_filename_in_diff(old.to_owned()),
+                1,
+            );
+        } else if let Some(new) = header_line.strip_prefix("+++ ") {
+            set_if_higher_priority(
+                &mut new_file_name,
+                &mut new_priority,
+                fix_filename_in_diff(new.to_owned()),
+                1,
+            );
+        } else if let Some(old) = header_line
+            .strip_prefix("rename from ")
+            .or_else(|| header_line.strip_prefix("copy from "))
+        {
+            set_if_higher_priority(&mut old_file_name, &mut old_priority, old.to_owned(), 2);
+        } else if let Some(new) = header_line
+            .strip_prefix("rename to ")
+            .or_else(|| header_line.strip_prefix("copy to "))
+        {
+            set_if_higher_priority(&mut new_file_name, &mut new_priority, new.to_owned(), 2);
+        }
+
+        current_file_contents.push_str(line);
     }
 
     if old_file_name.is_empty() || new_file_name.is_empty() {
@@ -174,12 +777,41 @@ fn split_diff(diff: String) -> anyhow::Result<Vec<PatchFile>> {
     patch_files.push(PatchFile {
         old: old_file_name.clone(),
         new: new_file_name.clone(),
-        contents: current_file_lines.join("\n"),
+        contents: current_file_contents,
     });
 
     Ok(patch_files)
 }
 
+// Only overwrites `name` if `priority` is at least as high as whatever set
+// it last, so a rename/copy header (priority 2) can't be clobbered by a
+// `--- `/`+++ ` pair (priority 1) that happens to appear afterward, e.g. on
+// a rename that also changed the file's contents.
+fn set_if_higher_priority(
+    name: &mut String,
+    current_priority: &mut i8,
+    value: String,
+    priority: i8,
+) {
+    if priority >= *current_priority {
+        *name = value;
+        *current_priority = priority;
+    }
+}
+
+// Parses the `diff --git a/<old> b/<new>` header line. This is only a
+// best-effort fallback used when neither a rename/copy header nor a
+// `--- `/`+++ ` pair is present; like git itself, we can't perfectly
+// disambiguate old/new when a path contains " b/" literally.
+fn parse_diff_git_header(line: &str) -> Option<(String, String)> {
+    let rest = line.strip_prefix("diff --git ")?;
+    let rest = rest.strip_prefix("a/").unwrap_or(rest);
+    let split_at = rest.find(" b/")?;
+    let old = rest[..split_at].to_owned();
+    let new = rest[split_at + 3..].to_owned();
+    Some((old, new))
+}
+
 fn fix_filename_in_diff(mut filename: String) -> String {
     // Prefixes used by git diff.
     if filename.starts_with("a/") || filename.starts_with("b/") {
@@ -194,9 +826,9 @@ fn fix_filename_in_diff(mut filename: String) -> String {
     filename
 }
 
-fn write_out_new_diffs(patch_files: Vec<PatchFile>) -> Result<(), io::Error> {
+fn write_out_new_diffs(patch_files: Vec<PatchFile>, output_dir: &Path) -> Result<(), io::Error> {
     for pf in patch_files {
-        let new_path = generate_filename(&pf)?;
+        let new_path = generate_filename(&pf, output_dir)?;
         info!("Writing: {}", new_path.to_string_lossy());
         // Theoretically there is a TOCTOU issue here.
         fs::write(new_path, pf.contents)?;
@@ -204,7 +836,7 @@ fn write_out_new_diffs(patch_files: Vec<PatchFile>) -> Result<(), io::Error> {
     Ok(())
 }
 
-fn generate_filename(pf: &PatchFile) -> Result<PathBuf, io::Error> {
+fn generate_filename(pf: &PatchFile, output_dir: &Path) -> Result<PathBuf, io::Error> {
     // By default, we want to use the new filename. However, in some patch
     // formats it's "/dev/null" for deleted files, and we don't just want
     // to write out a bunch of files named _dev_null.
@@ -222,7 +854,7 @@ fn generate_filename(pf: &PatchFile) -> Result<PathBuf, io::Error> {
             }
         })
         .collect();
-    let mut with_ext = Path::new(&base_filename).with_extension("diff");
+    let mut with_ext = output_dir.join(&base_filename).with_extension("diff");
     let mut counter = 0;
     // TODO: Add retry limit?
     loop {
@@ -247,6 +879,85 @@ mod tests {
     // This is the commit where we added all the files, but didn't modify them yet.
     const TEST_REPO_BASE_COMMIT: &str = "802a28339894a17bf824fb515415df565dd8ab5f";
 
+    fn default_cli() -> Cli {
+        Cli {
+            remote: DEFAULT_REMOTE.to_string(),
+            base: None,
+            staged: false,
+            worktree: false,
+            output_dir: PathBuf::from("."),
+            format_patch: false,
+            verify: false,
+            no_index: None,
+        }
+    }
+
+    // quote_mbox_from_lines / rfc2047_encode
+
+    #[gtest]
+    fn quote_mbox_from_lines_quotes_from_at_line_start() {
+        let body = "line one\n\nFrom a security standpoint, this matters.\nlast line";
+        expect_that!(
+            quote_mbox_from_lines(body),
+            eq("line one\n\n>From a security standpoint, this matters.\nlast line")
+        );
+    }
+
+    #[gtest]
+    fn quote_mbox_from_lines_adds_another_gt_to_already_quoted_lines() {
+        expect_that!(
+            quote_mbox_from_lines(">From the top\n"),
+            eq(">>From the top\n")
+        );
+    }
+
+    #[gtest]
+    fn quote_mbox_from_lines_leaves_unrelated_lines_alone() {
+        expect_that!(
+            quote_mbox_from_lines("Format the file please\nFromage is cheese\n"),
+            eq("Format the file please\nFromage is cheese\n")
+        );
+    }
+
+    #[gtest]
+    fn rfc2047_encode_passes_through_ascii() {
+        expect_that!(rfc2047_encode("Fix the foo bug"), eq("Fix the foo bug"));
+    }
+
+    #[gtest]
+    fn rfc2047_encode_encodes_non_ascii() {
+        expect_that!(rfc2047_encode("héllo"), eq("=?UTF-8?B?aMOpbGxv?="));
+    }
+
+    // slugify_subject / format_rfc2822
+
+    #[gtest]
+    fn slugify_subject_replaces_punctuation_and_spaces() {
+        expect_that!(slugify_subject("Fix the foo bug!"), eq("Fix-the-foo-bug"));
+    }
+
+    #[gtest]
+    fn slugify_subject_collapses_runs_of_punctuation() {
+        expect_that!(
+            slugify_subject("refactor: split_diff() -- take 2"),
+            eq("refactor-split_diff-take-2")
+        );
+    }
+
+    #[gtest]
+    fn format_rfc2822_formats_known_instant() {
+        // 2025-01-01T00:00:00+0000, a Wednesday.
+        let time = Time::new(1735689600, 0);
+        expect_that!(format_rfc2822(&time), eq("Wed, 01 Jan 2025 00:00:00 +0000"));
+    }
+
+    #[gtest]
+    fn format_rfc2822_applies_offset() {
+        // Same instant, but as seen from UTC-5 (e.g. US Eastern).
+        let time = Time::new(1735689600, -5 * 60);
+        expect_that!(format_rfc2822(&time), eq("Tue, 31 Dec 2024 19:00:00 -0500"));
+    }
+
     // generate_filename
 
     #[gtest]
@@ -256,7 +967,10 @@ mod tests {
             new: "bar".to_string(),
             contents: "nothing".to_string(),
         };
-        expect_that!(generate_filename(&pf), ok(eq(Path::new("bar.diff"))));
+        expect_that!(
+            generate_filename(&pf, Path::new(".")),
+            ok(eq(Path::new("bar.diff")))
+        );
     }
 
     #[gtest]
@@ -266,7 +980,10 @@ mod tests {
             new: "/dev/null".to_string(),
             contents: "nothing".to_string(),
         };
-        expect_that!(generate_filename(&pf), ok(eq(Path::new("foo.diff"))));
+        expect_that!(
+            generate_filename(&pf, Path::new(".")),
+            ok(eq(Path::new("foo.diff")))
+        );
     }
 
     #[gtest]
@@ -276,7 +993,10 @@ mod tests {
             new: "bar.diff".to_string(),
             contents: "nothing".to_string(),
         };
-        expect_that!(generate_filename(&pf), ok(eq(Path::new("bar_diff.diff"))));
+        expect_that!(
+            generate_filename(&pf, Path::new(".")),
+            ok(eq(Path::new("bar_diff.diff")))
+        );
     }
 
     #[gtest]
@@ -301,7 +1021,10 @@ mod tests {
             contents: "nothing".to_string(),
         };
         let expect_name = format!("{}-1.diff", without_ext);
-        expect_that!(generate_filename(&pf), ok(eq(Path::new(&expect_name))));
+        expect_that!(
+            generate_filename(&pf, Path::new(".")),
+            ok(eq(Path::new(&expect_name)))
+        );
     }
 
     // Git tests that use actual repos. Note: don't panic in any of these
@@ -343,7 +1066,7 @@ mod tests {
         clone
             .find_remote("origin")?
             .fetch::<&str>(&[], None, None)?;
-        let diff_text = get_diff_from_repo(&clone).into_test_result()?;
+        let diff_text = get_diff_from_repo(&clone, &default_cli()).into_test_result()?;
         let expected = fs::read_to_string("tests/fixtures/get_diff_from_repo_multi_file.diff")?;
         expect_eq!(diff_text, expected);
         Ok(())
@@ -352,23 +1075,218 @@ mod tests {
     #[gtest]
     fn get_diff_from_repo_no_changes() -> Result<()> {
         let (_tmp, _, clone) = test_tar_to_repo_and_clone().into_test_result()?;
-        let diff_text = get_diff_from_repo(&clone).into_test_result()?;
+        let diff_text = get_diff_from_repo(&clone, &default_cli()).into_test_result()?;
         expect_that!(diff_text, char_count(eq(0)));
         Ok(())
     }
 
+    #[gtest]
+    fn get_diff_from_repo_worktree_includes_committed_and_uncommitted_changes() -> Result<()> {
+        let (_tmp, origin, clone) = test_tar_to_repo_and_clone().into_test_result()?;
+        reset_repo_to_commit(&origin, TEST_REPO_BASE_COMMIT)?;
+        clone
+            .find_remote("origin")?
+            .fetch::<&str>(&[], None, None)?;
+
+        // An uncommitted, unstaged edit in the worktree.
+        let workdir = clone.workdir().unwrap();
+        fs::write(workdir.join("Cargo.toml"), "[worktree-edit]\n")?;
+
+        let mut cli = default_cli();
+        cli.worktree = true;
+        let diff_text = get_diff_from_repo(&clone, &cli).into_test_result()?;
+        // The worktree edit must show up...
+        expect_that!(diff_text, contains_substring("+[worktree-edit]"));
+        // ...as must everything already committed on top of the merge base.
+        expect_that!(diff_text, contains_substring("src/splitpr.rs"));
+        Ok(())
+    }
+
+    #[gtest]
+    fn get_diff_from_repo_staged_ignores_unstaged_worktree_edits() -> Result<()> {
+        let (_tmp, origin, clone) = test_tar_to_repo_and_clone().into_test_result()?;
+        reset_repo_to_commit(&origin, TEST_REPO_BASE_COMMIT)?;
+        clone
+            .find_remote("origin")?
+            .fetch::<&str>(&[], None, None)?;
+
+        let workdir = clone.workdir().unwrap();
+        fs::write(workdir.join("Cargo.toml"), "[staged-edit]\n")?;
+        let mut index = clone.index()?;
+        index.add_path(Path::new("Cargo.toml"))?;
+        index.write()?;
+
+        let mut cli = default_cli();
+        cli.staged = true;
+        let diff_text = get_diff_from_repo(&clone, &cli).into_test_result()?;
+        expect_that!(diff_text, contains_substring("+[staged-edit]"));
+
+        // Now make a further, unstaged edit; --staged must not pick it up.
+        fs::write(workdir.join("Cargo.toml"), "[unstaged-edit]\n")?;
+        let diff_text = get_diff_from_repo(&clone, &cli).into_test_result()?;
+        expect_that!(diff_text, contains_substring("+[staged-edit]"));
+        expect_that!(diff_text, not(contains_substring("+[unstaged-edit]")));
+        Ok(())
+    }
+
     #[gtest]
     fn get_diff_from_repo_no_remote() -> Result<()> {
         let (_tmp, origin, _) = test_tar_to_repo_and_clone().into_test_result()?;
-        let result = get_diff_from_repo(&origin);
+        let result = get_diff_from_repo(&origin, &default_cli());
+        expect_that!(result, err(anything()));
+        let error = format!("{:#}", result.unwrap_err());
+        expect_that!(
+            error,
+            contains_substring(format!("refs/remotes/{}/HEAD", DEFAULT_REMOTE))
+        );
+        Ok(())
+    }
+
+    #[gtest]
+    fn get_diff_from_repo_preserves_crlf_line_endings() -> Result<()> {
+        let (_tmp, origin, clone) = test_tar_to_repo_and_clone().into_test_result()?;
+        reset_repo_to_commit(&origin, TEST_REPO_BASE_COMMIT)?;
+        clone
+            .find_remote("origin")?
+            .fetch::<&str>(&[], None, None)?;
+
+        // An uncommitted edit to a CRLF file, written directly to the
+        // workdir (bypassing any checkout-time filters) so we know exactly
+        // what bytes are on disk.
+        let workdir = clone.workdir().unwrap();
+        fs::write(workdir.join("Cargo.toml"), "[package]\r\nname = \"x\"\r\n")?;
+
+        let mut cli = default_cli();
+        cli.worktree = true;
+        let diff_text = get_diff_from_repo(&clone, &cli).into_test_result()?;
+        // git2's tree/workdir diffing must hand us the CRLF bytes as-is,
+        // not normalized to LF.
+        expect_that!(diff_text, contains_substring("+[package]\r\n"));
+        expect_that!(diff_text, contains_substring("+name = \"x\"\r\n"));
+        Ok(())
+    }
+
+    // verify_patches_apply
+
+    #[gtest]
+    fn verify_patches_apply_accepts_well_formed_patches() -> Result<()> {
+        let (_tmp, origin, clone) = test_tar_to_repo_and_clone().into_test_result()?;
+        reset_repo_to_commit(&origin, TEST_REPO_BASE_COMMIT)?;
+        clone
+            .find_remote("origin")?
+            .fetch::<&str>(&[], None, None)?;
+        let diff_text = get_diff_from_repo(&clone, &default_cli()).into_test_result()?;
+        let patch_files = split_diff(diff_text).into_test_result()?;
+
+        let (_, merge_base_commit) =
+            find_merge_base_commits(&clone, DEFAULT_REMOTE, None).into_test_result()?;
+        let result = verify_patches_apply(&clone, &merge_base_commit.tree()?, &patch_files);
+        expect_that!(result, ok(anything()));
+        Ok(())
+    }
+
+    #[gtest]
+    fn verify_patches_apply_reports_the_offending_file() -> Result<()> {
+        let (_tmp, origin, clone) = test_tar_to_repo_and_clone().into_test_result()?;
+        reset_repo_to_commit(&origin, TEST_REPO_BASE_COMMIT)?;
+        clone
+            .find_remote("origin")?
+            .fetch::<&str>(&[], None, None)?;
+        let diff_text = get_diff_from_repo(&clone, &default_cli()).into_test_result()?;
+        let mut patch_files = split_diff(diff_text).into_test_result()?;
+
+        // Corrupt one patch's context/hunk so it can no longer apply against
+        // the merge base it claims to be based on.
+        let corrupted = &mut patch_files[0];
+        corrupted.contents = corrupted
+            .contents
+            .replace("@@ -1,", "@@ -999,999 +999,999 @@ garbage\n@@ -1,");
+        let corrupted_new = corrupted.new.clone();
+
+        let (_, merge_base_commit) =
+            find_merge_base_commits(&clone, DEFAULT_REMOTE, None).into_test_result()?;
+        let result = verify_patches_apply(&clone, &merge_base_commit.tree()?, &patch_files);
         expect_that!(result, err(anything()));
         let error = format!("{:#}", result.unwrap_err());
-        expect_that!(error, contains_substring(DEFAULT_REMOTE_HEAD));
+        expect_that!(error, contains_substring(&corrupted_new));
+        Ok(())
+    }
+
+    // diff_paths_no_index
+
+    #[gtest]
+    fn diff_paths_no_index_two_files() -> Result<()> {
+        let old_dir = TempDir::new().into_test_result()?;
+        let new_dir = TempDir::new().into_test_result()?;
+        let old_file = old_dir.path().join("a.txt");
+        let new_file = new_dir.path().join("b.txt");
+        fs::write(&old_file, "line one\nline two\n")?;
+        fs::write(&new_file, "line one\nline TWO\n")?;
+
+        let diff_text = diff_paths_no_index(&old_file, &new_file).into_test_result()?;
+        let patch_files = split_diff(diff_text).into_test_result()?;
+        assert_that!(patch_files, len(eq(1)));
+        expect_that!(
+            patch_files[0].contents,
+            contains_substring("-line two\n+line TWO\n")
+        );
+        Ok(())
+    }
+
+    #[gtest]
+    fn diff_paths_no_index_directories() -> Result<()> {
+        let old_dir = TempDir::new().into_test_result()?;
+        let new_dir = TempDir::new().into_test_result()?;
+        fs::write(old_dir.path().join("unchanged.txt"), "same\n")?;
+        fs::write(new_dir.path().join("unchanged.txt"), "same\n")?;
+        fs::write(old_dir.path().join("removed.txt"), "gone\n")?;
+        fs::write(new_dir.path().join("added.txt"), "new\n")?;
+        fs::create_dir(old_dir.path().join("sub"))?;
+        fs::create_dir(new_dir.path().join("sub"))?;
+        fs::write(old_dir.path().join("sub/nested.txt"), "before\n")?;
+        fs::write(new_dir.path().join("sub/nested.txt"), "after\n")?;
+
+        let diff_text = diff_paths_no_index(old_dir.path(), new_dir.path()).into_test_result()?;
+        let patch_files = split_diff(diff_text).into_test_result()?;
+        // unchanged.txt produces no diff at all; removed/added/nested do.
+        assert_that!(patch_files, len(eq(3)));
+        Ok(())
+    }
+
+    #[gtest]
+    fn diff_paths_no_index_rejects_symlinked_directory() -> Result<()> {
+        let old_dir = TempDir::new().into_test_result()?;
+        let new_dir = TempDir::new().into_test_result()?;
+        fs::write(old_dir.path().join("a.txt"), "same\n")?;
+        fs::write(new_dir.path().join("a.txt"), "same\n")?;
+        // A directory symlink back to its own parent: following it would
+        // recurse forever instead of erroring.
+        std::os::unix::fs::symlink(old_dir.path(), old_dir.path().join("cycle"))?;
+
+        let result = diff_paths_no_index(old_dir.path(), new_dir.path());
+        expect_that!(result, err(anything()));
         Ok(())
     }
 
     // split_diff
 
+    #[gtest]
+    fn split_diff_preserves_crlf_and_missing_trailing_newline() {
+        let diff = "diff --git a/f.txt b/f.txt\r\n\
+             --- a/f.txt\r\n\
+             +++ b/f.txt\r\n\
+             @@ -1,2 +1,2 @@\r\n\
+             -old\r\n\
+             +new\r\n\
+             no trailing newline"
+            .to_string();
+        let patch_files = split_diff(diff).unwrap();
+        assert_that!(patch_files, len(eq(1)));
+        expect_that!(patch_files[0].contents, contains_substring("-old\r\n"));
+        expect_that!(patch_files[0].contents, contains_substring("+new\r\n"));
+        assert!(patch_files[0].contents.ends_with("no trailing newline"));
+    }
+
     #[gtest]
     fn split_diff_git() {
         let diff = fs::read_to_string("tests/fixtures/git-multi-file.diff").unwrap();
@@ -378,16 +1296,15 @@ mod tests {
             &patch_files[0],
             "Cargo.toml",
             "Cargo.toml",
-            279,
+            280,
             "[[bin]]\n",
         );
-        // TODO: This does not preserve the newline on the last line, currently.
-        check_patch_file(&patch_files[1], "src/main.rs", "/dev/null", 181, "-}");
+        check_patch_file(&patch_files[1], "src/main.rs", "/dev/null", 182, "-}\n");
         check_patch_file(
             &patch_files[2],
             "/dev/null",
             "src/splitpr.rs",
-            416,
+            417,
             "+    Ok(())\n",
         );
     }
@@ -402,11 +1319,9 @@ mod tests {
             &patch_files[0],
             "multipr-2/Cargo.toml",
             "multipr-3/Cargo.toml",
-            332,
+            333,
             "[[bin]]\n",
         );
-        // TODO: This does not preserve the newline on the last line, currently.
-        //
         // Note that this is an important difference from git diff: there is no /dev/null when you're
         // adding or removing a file. Instead, the added and removed file name are the same but with
         // different base directories, as though there was an empty file in the new or old location.
@@ -414,18 +1329,56 @@ mod tests {
             &patch_files[1],
             "multipr-2/src/main.rs",
             "multipr-3/src/main.rs",
-            240,
-            "-}",
+            241,
+            "-}\n",
         );
         check_patch_file(
             &patch_files[2],
             "multipr-2/src/splitpr.rs",
             "multipr-3/src/splitpr.rs",
-            482,
+            483,
             "+    Ok(())\n",
         );
     }
 
+    // A pure rename (no content change) followed by a rename that also
+    // modifies the file's contents.
+    #[gtest]
+    fn split_diff_renames() {
+        let diff = fs::read_to_string("tests/fixtures/git-renames.diff").unwrap();
+        let patch_files = split_diff(diff).unwrap();
+        assert_that!(patch_files, len(eq(2)));
+        check_patch_file(
+            &patch_files[0],
+            "old/path.txt",
+            "new/path.txt",
+            111,
+            "rename to new/path.txt",
+        );
+        check_patch_file(
+            &patch_files[1],
+            "src/foo.rs",
+            "src/bar.rs",
+            226,
+            "+fn bar() {}",
+        );
+    }
+
+    // parse_diff_git_header
+
+    #[gtest]
+    fn parse_diff_git_header_simple() {
+        expect_that!(
+            parse_diff_git_header("diff --git a/src/foo.rs b/src/bar.rs"),
+            some(eq(("src/foo.rs".to_string(), "src/bar.rs".to_string())))
+        );
+    }
+
+    #[gtest]
+    fn parse_diff_git_header_not_a_header() {
+        expect_that!(parse_diff_git_header("--- a/src/foo.rs"), none());
+    }
+
     fn check_patch_file(
         item: &PatchFile,
         old: &str,